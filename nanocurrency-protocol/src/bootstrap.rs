@@ -0,0 +1,639 @@
+// The bootstrap messages (bulk_pull, bulk_push, frontier_req) are carried
+// over a dedicated TCP connection rather than the UDP gossip socket, and
+// unlike the other messages they are followed by a stream of records rather
+// than being self-contained datagrams. That needs its own `Decoder`/`Encoder`
+// pair with real framing state, so it lives apart from `NanoCurrencyCodec`.
+
+use std::io;
+use std::io::Cursor;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use bytes::{BufMut, BytesMut};
+
+use nanocurrency_types::*;
+
+use tokio_codec;
+
+use super::{MessageHeader, NanoCurrencyCodec, NET_VERSION, NET_VERSION_MAX, NET_VERSION_MIN};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum BootstrapMessage {
+    FrontierReq {
+        start: Account,
+        age: u32,
+        count: u32,
+    },
+    FrontierReqEntry {
+        account: Account,
+        frontier: BlockHash,
+    },
+    FrontierReqEnd,
+    BulkPull {
+        start: Account,
+        end: BlockHash,
+    },
+    BulkPullEntry(Block),
+    BulkPullEnd,
+    BulkPush,
+    BulkPushEntry(Block),
+    BulkPushEnd,
+}
+
+#[derive(Clone)]
+enum BootstrapCodecState {
+    // Waiting for the next framed frontier_req/bulk_pull/bulk_push request.
+    Header,
+    // Reading (account, frontier hash) pairs until an all-zero terminator.
+    FrontierStream(MessageHeader),
+    // Reading block-type-prefixed blocks until a not_a_block (0) terminator.
+    BulkPullStream(MessageHeader),
+    BulkPushStream(MessageHeader),
+}
+
+impl Default for BootstrapCodecState {
+    fn default() -> Self {
+        BootstrapCodecState::Header
+    }
+}
+
+/// Speaks the stream-oriented bootstrap protocol (frontier_req, bulk_pull,
+/// bulk_push) over a TCP connection. Unlike `NanoCurrencyCodec` this codec is
+/// stateful: encoding a frontier_req or bulk_pull request, or decoding a
+/// bulk_push request, switches it into the matching streaming mode until
+/// that stream's terminator record is seen.
+#[derive(Default)]
+pub struct BootstrapCodec {
+    state: BootstrapCodecState,
+}
+
+impl BootstrapCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Size in bytes of a full on-wire block (inner fields + signature +
+    /// work), not including the leading block-type byte.
+    fn block_len(block_ty: u8) -> io::Result<usize> {
+        let inner_len = match block_ty {
+            2 => 32 + 32 + 16,           // send: previous, destination, balance
+            3 => 32 + 32,                // receive: previous, source
+            4 => 32 + 32 + 32,           // open: source, representative, account
+            5 => 32 + 32,                // change: previous, representative
+            6 => 32 + 32 + 32 + 16 + 32, // state: account, previous, representative, balance, link
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "unrecognized block type",
+                ))
+            }
+        };
+        Ok(inner_len + 64 + 8) // + signature + work
+    }
+
+    fn decode_header(
+        &mut self,
+        buf: &mut BytesMut,
+    ) -> io::Result<Option<(MessageHeader, BootstrapMessage)>> {
+        if buf.len() < 8 {
+            return Ok(None);
+        }
+        if buf[0] != b'R' {
+            return Err(io::Error::new(io::ErrorKind::Other, "invalid magic number"));
+        }
+        let network = match buf[1] {
+            b'A' => Network::Test,
+            b'B' => Network::Beta,
+            b'C' => Network::Live,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "invalid network indicator",
+                ))
+            }
+        };
+        let version_max = buf[2];
+        let version = buf[3];
+        let version_min = buf[4];
+        let msg_type = buf[5];
+        let extensions = LittleEndian::read_u16(&buf[6..8]);
+        if version_min > NET_VERSION_MAX || version_max < NET_VERSION_MIN {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "unsupported peer version",
+            ));
+        }
+        let body_len = match msg_type {
+            6 => 64, // bulk_pull: start + end
+            7 => 0,  // bulk_push: no body
+            8 => 40, // frontier_req: start + age + count
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "not a bootstrap message type",
+                ))
+            }
+        };
+        if buf.len() < 8 + body_len {
+            return Ok(None);
+        }
+        buf.split_to(8);
+        let header = MessageHeader {
+            network,
+            version_max,
+            version,
+            version_min,
+            extensions,
+        };
+        let message = match msg_type {
+            6 => {
+                // We're the responder here: we go on to *encode* the entry
+                // stream, not decode it, so our own state stays at Header
+                // (see Encoder::encode, which seeds BulkPullStream on the
+                // requester's codec instead).
+                let body = buf.split_to(64);
+                let mut start = Account::default();
+                start.0.copy_from_slice(&body[0..32]);
+                let mut end = BlockHash::default();
+                end.0.copy_from_slice(&body[32..64]);
+                BootstrapMessage::BulkPull { start, end }
+            }
+            7 => {
+                // Unlike bulk_pull/frontier_req, we're the side that keeps
+                // decoding here: bulk_push uploads the blocks to us.
+                self.state = BootstrapCodecState::BulkPushStream(header.clone());
+                BootstrapMessage::BulkPush
+            }
+            8 => {
+                // See the bulk_pull arm above: the responder only encodes
+                // the frontier stream back, it never decodes it.
+                let body = buf.split_to(40);
+                let mut start = Account::default();
+                start.0.copy_from_slice(&body[0..32]);
+                let age = LittleEndian::read_u32(&body[32..36]);
+                let count = LittleEndian::read_u32(&body[36..40]);
+                BootstrapMessage::FrontierReq { start, age, count }
+            }
+            _ => unreachable!(),
+        };
+        Ok(Some((header, message)))
+    }
+
+    fn decode_frontier_entry(
+        &mut self,
+        header: MessageHeader,
+        buf: &mut BytesMut,
+    ) -> io::Result<Option<(MessageHeader, BootstrapMessage)>> {
+        if buf.len() < 64 {
+            return Ok(None);
+        }
+        let body = buf.split_to(64);
+        if body.iter().all(|&b| b == 0) {
+            self.state = BootstrapCodecState::Header;
+            return Ok(Some((header, BootstrapMessage::FrontierReqEnd)));
+        }
+        let mut account = Account::default();
+        account.0.copy_from_slice(&body[0..32]);
+        let mut frontier = BlockHash::default();
+        frontier.0.copy_from_slice(&body[32..64]);
+        Ok(Some((
+            header,
+            BootstrapMessage::FrontierReqEntry { account, frontier },
+        )))
+    }
+
+    /// Reads one entry of a bulk_pull/bulk_push block stream. `None` means
+    /// the buffered bytes aren't a full record yet; the caller should leave
+    /// `buf` untouched and wait for more to arrive.
+    fn decode_block_entry(&mut self, buf: &mut BytesMut) -> io::Result<Option<BlockStreamEntry>> {
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        let block_ty = buf[0];
+        if block_ty == 0 {
+            // not_a_block: stream terminator
+            buf.split_to(1);
+            return Ok(Some(BlockStreamEntry::End));
+        }
+        let total_len = 1 + Self::block_len(block_ty)?;
+        if buf.len() < total_len {
+            return Ok(None);
+        }
+        buf.split_to(1);
+        let body = buf.split_to(total_len - 1);
+        let mut cursor = Cursor::new(&body[..]);
+        let block = NanoCurrencyCodec::read_block(&mut cursor, block_ty)?;
+        Ok(Some(BlockStreamEntry::Block(block)))
+    }
+
+    /// Header describing a message we ourselves are about to send, used to
+    /// seed streaming decoder state on the requesting side (there's no framed
+    /// header on the wire to read it back from).
+    fn local_header(network: Network) -> MessageHeader {
+        MessageHeader {
+            network,
+            version_max: NET_VERSION_MAX,
+            version: NET_VERSION,
+            version_min: NET_VERSION_MIN,
+            extensions: 0,
+        }
+    }
+
+    fn write_header(buf: &mut BytesMut, network: Network, msg_type: u8) {
+        buf.reserve(8);
+        buf.put_slice(&[
+            b'R',
+            NanoCurrencyCodec::network_magic_byte(network),
+            NET_VERSION_MAX,
+            NET_VERSION,
+            NET_VERSION_MIN,
+            msg_type,
+        ]);
+        buf.put_u16_le(0); // extensions
+    }
+
+    fn write_block_entry(buf: &mut BytesMut, block: Block) {
+        let type_num = NanoCurrencyCodec::block_type_num(&block);
+        buf.reserve(1);
+        buf.put_slice(&[type_num]);
+        NanoCurrencyCodec::write_block(buf, block);
+    }
+}
+
+enum BlockStreamEntry {
+    Block(Block),
+    End,
+}
+
+impl tokio_codec::Decoder for BootstrapCodec {
+    type Item = (MessageHeader, BootstrapMessage);
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+        match self.state.clone() {
+            BootstrapCodecState::Header => self.decode_header(buf),
+            BootstrapCodecState::FrontierStream(header) => self.decode_frontier_entry(header, buf),
+            BootstrapCodecState::BulkPullStream(header) => match self.decode_block_entry(buf)? {
+                None => Ok(None),
+                Some(BlockStreamEntry::End) => {
+                    self.state = BootstrapCodecState::Header;
+                    Ok(Some((header, BootstrapMessage::BulkPullEnd)))
+                }
+                Some(BlockStreamEntry::Block(block)) => {
+                    Ok(Some((header, BootstrapMessage::BulkPullEntry(block))))
+                }
+            },
+            BootstrapCodecState::BulkPushStream(header) => match self.decode_block_entry(buf)? {
+                None => Ok(None),
+                Some(BlockStreamEntry::End) => {
+                    self.state = BootstrapCodecState::Header;
+                    Ok(Some((header, BootstrapMessage::BulkPushEnd)))
+                }
+                Some(BlockStreamEntry::Block(block)) => {
+                    Ok(Some((header, BootstrapMessage::BulkPushEntry(block))))
+                }
+            },
+        }
+    }
+}
+
+impl tokio_codec::Encoder for BootstrapCodec {
+    type Item = (Network, BootstrapMessage);
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: Self::Item, buf: &mut BytesMut) -> io::Result<()> {
+        let (network, message) = msg;
+        match message {
+            BootstrapMessage::FrontierReq { start, age, count } => {
+                // We're the requester: switch straight into streaming mode so
+                // the entries the peer sends back decode correctly, since we
+                // won't see another framed header on this connection until
+                // the stream's terminator record.
+                self.state = BootstrapCodecState::FrontierStream(Self::local_header(network));
+                Self::write_header(buf, network, 8);
+                buf.reserve(40);
+                buf.put_slice(&start.0);
+                buf.put_u32_le(age);
+                buf.put_u32_le(count);
+            }
+            BootstrapMessage::FrontierReqEntry { account, frontier } => {
+                buf.reserve(64);
+                buf.put_slice(&account.0);
+                buf.put_slice(&frontier.0);
+            }
+            BootstrapMessage::FrontierReqEnd => {
+                buf.reserve(64);
+                buf.put_slice(&[0u8; 64]);
+            }
+            BootstrapMessage::BulkPull { start, end } => {
+                // Same reasoning as frontier_req: we're the requester, so we
+                // need to be ready to decode the block stream the peer sends
+                // back rather than another framed header.
+                self.state = BootstrapCodecState::BulkPullStream(Self::local_header(network));
+                Self::write_header(buf, network, 6);
+                buf.reserve(64);
+                buf.put_slice(&start.0);
+                buf.put_slice(&end.0);
+            }
+            BootstrapMessage::BulkPullEntry(block) => Self::write_block_entry(buf, block),
+            BootstrapMessage::BulkPullEnd => buf.put_slice(&[0]),
+            BootstrapMessage::BulkPush => Self::write_header(buf, network, 7),
+            BootstrapMessage::BulkPushEntry(block) => Self::write_block_entry(buf, block),
+            BootstrapMessage::BulkPushEnd => buf.put_slice(&[0]),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_codec::{Decoder, Encoder};
+
+    fn sample_block() -> Block {
+        Block {
+            header: BlockHeader {
+                signature: Signature::from_bytes(&[0u8; 64]).unwrap(),
+                work: 0x1234_5678_9abc_def0,
+            },
+            inner: BlockInner::State {
+                account: Account([1u8; 32]),
+                previous: BlockHash([2u8; 32]),
+                representative: Account([3u8; 32]),
+                balance: 42,
+                link: [4u8; 32],
+            },
+        }
+    }
+
+    // Feeds `full` into `codec.decode()` one byte at a time, asserting every
+    // prefix short of the whole buffer yields `Ok(None)` without losing any
+    // bytes, then returns the final decoded item.
+    fn decode_byte_by_byte<T: Decoder<Error = io::Error>>(codec: &mut T, full: &[u8]) -> T::Item {
+        let mut buf = BytesMut::new();
+        for (i, &byte) in full.iter().enumerate() {
+            buf.reserve(1);
+            buf.put_slice(&[byte]);
+            let result = codec.decode(&mut buf).unwrap();
+            if i + 1 < full.len() {
+                assert!(result.is_none(), "decoded early at byte {}", i);
+            } else {
+                return result.expect("final byte should complete the record");
+            }
+        }
+        unreachable!("full buffer must be non-empty")
+    }
+
+    #[test]
+    fn frontier_req_request_roundtrip_partial_reads() {
+        let mut requester = BootstrapCodec::new();
+        let mut buf = BytesMut::new();
+        requester
+            .encode(
+                (
+                    Network::Test,
+                    BootstrapMessage::FrontierReq {
+                        start: Account([9u8; 32]),
+                        age: 0xffff_ffff,
+                        count: 1234,
+                    },
+                ),
+                &mut buf,
+            )
+            .unwrap();
+
+        let mut responder = BootstrapCodec::new();
+        let (_, message) = decode_byte_by_byte(&mut responder, &buf);
+        assert_eq!(
+            message,
+            BootstrapMessage::FrontierReq {
+                start: Account([9u8; 32]),
+                age: 0xffff_ffff,
+                count: 1234,
+            }
+        );
+
+        // Regression: the responder only ever *encodes* the frontier stream
+        // back, so its own decoder must stay in Header state, ready for the
+        // next framed request on this connection rather than stuck
+        // interpreting it as stream bytes.
+        let mut next_request_buf = BytesMut::new();
+        let mut next_requester = BootstrapCodec::new();
+        next_requester
+            .encode(
+                (
+                    Network::Test,
+                    BootstrapMessage::BulkPull {
+                        start: Account([7u8; 32]),
+                        end: BlockHash([8u8; 32]),
+                    },
+                ),
+                &mut next_request_buf,
+            )
+            .unwrap();
+        let (_, next_message) = decode_byte_by_byte(&mut responder, &next_request_buf);
+        assert_eq!(
+            next_message,
+            BootstrapMessage::BulkPull {
+                start: Account([7u8; 32]),
+                end: BlockHash([8u8; 32]),
+            }
+        );
+    }
+
+    #[test]
+    fn frontier_req_response_stream_decodes_on_requester_side() {
+        // Regression test: after sending a frontier_req, the requester's own
+        // codec must already be in streaming mode, since it never decodes
+        // another framed header before the terminator.
+        let mut requester = BootstrapCodec::new();
+        let mut request_buf = BytesMut::new();
+        requester
+            .encode(
+                (
+                    Network::Test,
+                    BootstrapMessage::FrontierReq {
+                        start: Account([0u8; 32]),
+                        age: 0,
+                        count: 0,
+                    },
+                ),
+                &mut request_buf,
+            )
+            .unwrap();
+
+        let mut responder = BootstrapCodec::new();
+        let mut stream_buf = BytesMut::new();
+        responder
+            .encode(
+                (
+                    Network::Test,
+                    BootstrapMessage::FrontierReqEntry {
+                        account: Account([5u8; 32]),
+                        frontier: BlockHash([6u8; 32]),
+                    },
+                ),
+                &mut stream_buf,
+            )
+            .unwrap();
+        responder
+            .encode(
+                (Network::Test, BootstrapMessage::FrontierReqEnd),
+                &mut stream_buf,
+            )
+            .unwrap();
+
+        let (_, entry) = decode_byte_by_byte(&mut requester, &stream_buf[0..64]);
+        assert_eq!(
+            entry,
+            BootstrapMessage::FrontierReqEntry {
+                account: Account([5u8; 32]),
+                frontier: BlockHash([6u8; 32]),
+            }
+        );
+
+        let (_, end) = decode_byte_by_byte(&mut requester, &stream_buf[64..128]);
+        assert_eq!(end, BootstrapMessage::FrontierReqEnd);
+    }
+
+    #[test]
+    fn bulk_pull_request_roundtrip_partial_reads() {
+        let mut requester = BootstrapCodec::new();
+        let mut buf = BytesMut::new();
+        requester
+            .encode(
+                (
+                    Network::Live,
+                    BootstrapMessage::BulkPull {
+                        start: Account([1u8; 32]),
+                        end: BlockHash([2u8; 32]),
+                    },
+                ),
+                &mut buf,
+            )
+            .unwrap();
+
+        let mut responder = BootstrapCodec::new();
+        let (_, message) = decode_byte_by_byte(&mut responder, &buf);
+        assert_eq!(
+            message,
+            BootstrapMessage::BulkPull {
+                start: Account([1u8; 32]),
+                end: BlockHash([2u8; 32]),
+            }
+        );
+
+        // Regression: the responder only ever *encodes* the block stream
+        // back, so its own decoder must stay in Header state, ready for the
+        // next framed request pipelined on this connection.
+        let mut next_request_buf = BytesMut::new();
+        let mut next_requester = BootstrapCodec::new();
+        next_requester
+            .encode(
+                (
+                    Network::Live,
+                    BootstrapMessage::FrontierReq {
+                        start: Account([3u8; 32]),
+                        age: 5,
+                        count: 6,
+                    },
+                ),
+                &mut next_request_buf,
+            )
+            .unwrap();
+        let (_, next_message) = decode_byte_by_byte(&mut responder, &next_request_buf);
+        assert_eq!(
+            next_message,
+            BootstrapMessage::FrontierReq {
+                start: Account([3u8; 32]),
+                age: 5,
+                count: 6,
+            }
+        );
+    }
+
+    #[test]
+    fn bulk_pull_response_stream_decodes_on_requester_side() {
+        let mut requester = BootstrapCodec::new();
+        let mut request_buf = BytesMut::new();
+        requester
+            .encode(
+                (
+                    Network::Live,
+                    BootstrapMessage::BulkPull {
+                        start: Account([0u8; 32]),
+                        end: BlockHash([0u8; 32]),
+                    },
+                ),
+                &mut request_buf,
+            )
+            .unwrap();
+
+        let mut responder = BootstrapCodec::new();
+        let mut stream_buf = BytesMut::new();
+        responder
+            .encode(
+                (
+                    Network::Live,
+                    BootstrapMessage::BulkPullEntry(sample_block()),
+                ),
+                &mut stream_buf,
+            )
+            .unwrap();
+        responder
+            .encode(
+                (Network::Live, BootstrapMessage::BulkPullEnd),
+                &mut stream_buf,
+            )
+            .unwrap();
+
+        let block_wire_len = stream_buf.len() - 1;
+        let (_, entry) = decode_byte_by_byte(&mut requester, &stream_buf[0..block_wire_len]);
+        assert_eq!(entry, BootstrapMessage::BulkPullEntry(sample_block()));
+
+        let (_, end) = decode_byte_by_byte(&mut requester, &stream_buf[block_wire_len..]);
+        assert_eq!(end, BootstrapMessage::BulkPullEnd);
+    }
+
+    #[test]
+    fn bulk_push_request_and_stream_roundtrip_partial_reads() {
+        // bulk_push is the inverse of bulk_pull: the side that decodes the
+        // framed request is the one that keeps decoding the pushed blocks.
+        let mut pusher = BootstrapCodec::new();
+        let mut request_buf = BytesMut::new();
+        pusher
+            .encode(
+                (Network::Beta, BootstrapMessage::BulkPush),
+                &mut request_buf,
+            )
+            .unwrap();
+
+        let mut stream_buf = BytesMut::new();
+        pusher
+            .encode(
+                (
+                    Network::Beta,
+                    BootstrapMessage::BulkPushEntry(sample_block()),
+                ),
+                &mut stream_buf,
+            )
+            .unwrap();
+        pusher
+            .encode(
+                (Network::Beta, BootstrapMessage::BulkPushEnd),
+                &mut stream_buf,
+            )
+            .unwrap();
+
+        let mut receiver = BootstrapCodec::new();
+        let (_, request) = decode_byte_by_byte(&mut receiver, &request_buf);
+        assert_eq!(request, BootstrapMessage::BulkPush);
+
+        let block_wire_len = stream_buf.len() - 1;
+        let (_, entry) = decode_byte_by_byte(&mut receiver, &stream_buf[0..block_wire_len]);
+        assert_eq!(entry, BootstrapMessage::BulkPushEntry(sample_block()));
+
+        let (_, end) = decode_byte_by_byte(&mut receiver, &stream_buf[block_wire_len..]);
+        assert_eq!(end, BootstrapMessage::BulkPushEnd);
+    }
+}
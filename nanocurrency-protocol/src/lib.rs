@@ -18,6 +18,9 @@ use bytes::{BufMut, BytesMut};
 extern crate nanocurrency_types;
 use nanocurrency_types::*;
 
+mod bootstrap;
+pub use bootstrap::{BootstrapCodec, BootstrapMessage};
+
 #[cfg(test)]
 mod tests;
 